@@ -10,17 +10,23 @@ use crate::common::types::cached_header_metadata::CachedHeaderMetadata;
 use crate::common::types::new_block_state::NewBlockState;
 use crate::db::Data;
 use kvdb::{DBTransaction, KeyValueDB};
+use lru::LruCache;
 use parity_scale_codec::{Decode, Encode};
+use parking_lot::Mutex;
 use sp_runtime::generic::BlockId;
 use sp_runtime::traits::{Block as BlockT, Header as HeaderT, NumberFor, One, Zero};
+use std::collections::HashMap;
 use std::io;
+use std::num::NonZeroUsize;
 
 const META_COLUMN: u32 = 0;
 const HEADER_COLUMN: u32 = 1;
 const AUX_COLUMN: u32 = 2;
 const LOOKUP_COLUMN: u32 = 3;
+const CHILDREN_COLUMN: u32 = 4;
 
 const META_KEY: &[u8] = b"meta";
+const LEAVES_KEY: &[u8] = b"leaves";
 
 /// Database metadata.
 #[derive(Debug, Encode, Decode)]
@@ -41,6 +47,85 @@ where
     pub genesis_hash: H,
     /// Non finalized blocks at the moment
     pub non_finalized_blocks: u64,
+    /// Number of the oldest finalized header still retained in the database.
+    pub oldest_retained_number: N,
+}
+
+/// Controls how much finalized history is retained once a header is pruned.
+#[derive(Debug, Clone)]
+pub enum PruningMode<N> {
+    /// Never delete a finalized header.
+    Archive,
+    /// Keep only the most recent `keep_finalized_blocks` finalized headers,
+    /// pruning anything older on each finalization.
+    Keep { keep_finalized_blocks: N },
+}
+
+/// A block hash together with its number, as found along a tree route.
+#[derive(Debug, Clone)]
+pub struct HashAndNumber<Block>
+where
+    Block: BlockT,
+{
+    /// Hash of the block.
+    pub hash: Block::Hash,
+    /// Number of the block.
+    pub number: NumberFor<Block>,
+}
+
+/// A tree route connecting two blocks that are both known to the backend.
+///
+/// The route is computed by walking both endpoints back towards genesis until
+/// they meet at their common ancestor (the "pivot"). `retracted` lists the
+/// blocks that would need to be left behind when moving from `from` to `to`,
+/// and `enacted` lists the blocks that would need to be applied.
+pub struct TreeRoute<Block>
+where
+    Block: BlockT,
+{
+    route: Vec<HashAndNumber<Block>>,
+    pivot: usize,
+}
+
+impl<Block> TreeRoute<Block>
+where
+    Block: BlockT,
+{
+    /// The common ancestor of the two blocks this route connects.
+    pub fn common_block(&self) -> &HashAndNumber<Block> {
+        self.route
+            .get(self.pivot)
+            .expect("tree route pivot always points into route; qed")
+    }
+
+    /// Blocks (in descending order) that are being left behind, excluding the common ancestor.
+    pub fn retracted(&self) -> &[HashAndNumber<Block>] {
+        &self.route[..self.pivot]
+    }
+
+    /// Blocks (in ascending order) that are being applied, excluding the common ancestor.
+    pub fn enacted(&self) -> &[HashAndNumber<Block>] {
+        &self.route[self.pivot + 1..]
+    }
+}
+
+/// Pending, not-yet-committed state for a batch of header imports.
+///
+/// Holds a single [`DBTransaction`] that accumulates every write for the whole
+/// batch, plus an in-memory overlay of the meta/leaves/header/children state
+/// that has been changed so far but not yet flushed to the database. Reads
+/// made while building the batch (e.g. parent lookups, tree routes) consult
+/// the overlay before falling back to disk, so headers later in the batch see
+/// the effects of headers already processed earlier in the same batch.
+struct UpdateContext<Block>
+where
+    Block: BlockT,
+{
+    tx: DBTransaction,
+    meta: StorageMeta<NumberFor<Block>, Block::Hash>,
+    leaves: Vec<Block::Hash>,
+    headers: HashMap<Block::Hash, Block::Header>,
+    children: HashMap<Block::Hash, Vec<Block::Hash>>,
 }
 
 fn db_err(err: io::Error) -> BlockchainError {
@@ -51,19 +136,51 @@ fn codec_error(err: parity_scale_codec::Error) -> BlockchainError {
     BlockchainError::DataDecode(err.to_string())
 }
 
-pub struct Storage {
+/// Default capacity of the in-memory header/metadata caches, used when the
+/// caller passes `0` to `Storage::new`.
+const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+pub struct Storage<Block>
+where
+    Block: BlockT,
+{
     data: Data,
     max_non_finalized_blocks_allowed: u64,
+    header_cache: Mutex<LruCache<Block::Hash, Block::Header>>,
+    header_metadata_cache: Mutex<LruCache<Block::Hash, CachedHeaderMetadata<Block>>>,
+    pruning: PruningMode<NumberFor<Block>>,
 }
 
-impl Storage {
-    pub fn new(data: Data, max_non_finalized_blocks_allowed: u64) -> Self {
+impl<Block> Storage<Block>
+where
+    Block: BlockT,
+{
+    pub fn new(
+        data: Data,
+        max_non_finalized_blocks_allowed: u64,
+        cache_capacity: usize,
+        pruning: PruningMode<NumberFor<Block>>,
+    ) -> Self {
+        let capacity =
+            NonZeroUsize::new(cache_capacity).unwrap_or_else(|| NonZeroUsize::new(DEFAULT_CACHE_CAPACITY).unwrap());
         Self {
             data,
             max_non_finalized_blocks_allowed,
+            header_cache: Mutex::new(LruCache::new(capacity)),
+            header_metadata_cache: Mutex::new(LruCache::new(capacity)),
+            pruning,
         }
     }
 
+    /// Number of the oldest finalized header still available in the database.
+    /// `Zero` if nothing has been finalized yet.
+    pub fn oldest_retained_number(&self) -> BlockchainResult<NumberFor<Block>> {
+        Ok(self
+            .fetch_meta::<NumberFor<Block>, Block::Hash>()?
+            .map(|meta| meta.oldest_retained_number)
+            .unwrap_or_else(Zero::zero))
+    }
+
     fn fetch_meta<N, H>(&self) -> BlockchainResult<Option<StorageMeta<N, H>>>
     where
         N: Encode + Decode,
@@ -98,35 +215,31 @@ impl Storage {
         tx.put(META_COLUMN, META_KEY, meta.encode().as_slice());
     }
 
-    fn tx_store_header<Block>(tx: &mut DBTransaction, header: &Block::Header)
-    where
-        Block: BlockT,
-    {
-        let id = Self::header_hash_to_id::<Block>(&header.hash());
+    fn tx_store_header(tx: &mut DBTransaction, header: &Block::Header) {
+        let id = Self::header_hash_to_id(&header.hash());
         tx.put(HEADER_COLUMN, id.as_slice(), header.encode().as_slice());
     }
 
-    fn tx_delete_header<Block>(tx: &mut DBTransaction, hash: &Block::Hash)
-    where
-        Block: BlockT,
-    {
-        let id = Self::header_hash_to_id::<Block>(hash);
+    fn tx_delete_header(tx: &mut DBTransaction, hash: &Block::Hash) {
+        let id = Self::header_hash_to_id(hash);
         tx.delete(HEADER_COLUMN, id.as_slice());
     }
 
-    fn header_hash_to_id<Block>(hash: &Block::Hash) -> Vec<u8>
-    where
-        Block: BlockT,
-    {
+    /// Evict a header and its cached metadata from the in-memory caches. Must be
+    /// called whenever a header is deleted from the backing store, so the cache
+    /// can never serve a block the database no longer has.
+    fn evict_header_cache(&self, hash: &Block::Hash) {
+        self.header_cache.lock().pop(hash);
+        self.header_metadata_cache.lock().pop(hash);
+    }
+
+    fn header_hash_to_id(hash: &Block::Hash) -> Vec<u8> {
         hash.encode()
     }
 
-    fn id<Block>(&self, block_id: BlockId<Block>) -> BlockchainResult<Option<Vec<u8>>>
-    where
-        Block: BlockT,
-    {
+    fn id(&self, block_id: BlockId<Block>) -> BlockchainResult<Option<Vec<u8>>> {
         match block_id {
-            BlockId::Hash(h) => Ok(Some(Self::header_hash_to_id::<Block>(&h))),
+            BlockId::Hash(h) => Ok(Some(Self::header_hash_to_id(&h))),
             BlockId::Number(n) => {
                 let data = self
                     .data
@@ -142,10 +255,7 @@ impl Storage {
         }
     }
 
-    fn header_hash<Block>(&self, number: NumberFor<Block>) -> BlockchainResult<Option<Block::Hash>>
-    where
-        Block: BlockT,
-    {
+    fn header_hash(&self, number: NumberFor<Block>) -> BlockchainResult<Option<Block::Hash>> {
         let data = self
             .data
             .db
@@ -160,9 +270,433 @@ impl Storage {
             ))
         }
     }
+
+    /// Look up a header, preferring the not-yet-committed overlay of `ctx` (if
+    /// given) over the backing database.
+    fn header_or_overlay(
+        &self,
+        ctx: Option<&UpdateContext<Block>>,
+        hash: Block::Hash,
+    ) -> BlockchainResult<Option<Block::Header>> {
+        if let Some(ctx) = ctx {
+            if let Some(header) = ctx.headers.get(&hash) {
+                return Ok(Some(header.clone()));
+            }
+        }
+        self.header(BlockId::<Block>::Hash(hash))
+    }
+
+    /// Look up the recorded children of `parent`, preferring the overlay of
+    /// `ctx` (if given) over the backing database.
+    fn children_or_overlay(
+        &self,
+        ctx: Option<&UpdateContext<Block>>,
+        parent: &Block::Hash,
+    ) -> BlockchainResult<Vec<Block::Hash>> {
+        if let Some(ctx) = ctx {
+            if let Some(children) = ctx.children.get(parent) {
+                return Ok(children.clone());
+            }
+        }
+        self.fetch_children(parent)
+    }
+
+    fn header_and_number(
+        &self,
+        ctx: Option<&UpdateContext<Block>>,
+        hash: Block::Hash,
+    ) -> BlockchainResult<(Block::Header, HashAndNumber<Block>)> {
+        let possible_header = self.header_or_overlay(ctx, hash)?;
+        if possible_header.is_none() {
+            return Err(BlockchainError::UnknownBlock(format!(
+                "Could not find header {} while computing tree route",
+                hash
+            )));
+        }
+        let header = possible_header.unwrap();
+        let number = *header.number();
+        Ok((header, HashAndNumber { hash, number }))
+    }
+
+    /// Compute the route connecting `from` and `to` through their common ancestor.
+    ///
+    /// Both endpoints are walked back towards genesis: first bringing the deeper
+    /// one up to the shallower one's number by following parent hashes, then
+    /// stepping both back in lockstep until the hashes match. Fails with
+    /// `UnknownBlock` if any header along either path is missing. When `ctx` is
+    /// given, headers pending in its overlay are visible even though they have
+    /// not been committed to the database yet.
+    fn tree_route(
+        &self,
+        ctx: Option<&UpdateContext<Block>>,
+        from: Block::Hash,
+        to: Block::Hash,
+    ) -> BlockchainResult<TreeRoute<Block>> {
+        let (mut from_header, mut from) = self.header_and_number(ctx, from)?;
+        let (mut to_header, mut to) = self.header_and_number(ctx, to)?;
+
+        let mut from_branch = Vec::new();
+        let mut to_branch = Vec::new();
+
+        while to.number > from.number {
+            to_branch.push(to.clone());
+            let (header, next) = self.header_and_number(ctx, *to_header.parent_hash())?;
+            to_header = header;
+            to = next;
+        }
+
+        while from.number > to.number {
+            from_branch.push(from.clone());
+            let (header, next) = self.header_and_number(ctx, *from_header.parent_hash())?;
+            from_header = header;
+            from = next;
+        }
+
+        while from.hash != to.hash {
+            from_branch.push(from.clone());
+            let (header, next) = self.header_and_number(ctx, *from_header.parent_hash())?;
+            from_header = header;
+            from = next;
+
+            to_branch.push(to.clone());
+            let (header, next) = self.header_and_number(ctx, *to_header.parent_hash())?;
+            to_header = header;
+            to = next;
+        }
+
+        let pivot = from_branch.len();
+        let mut route = from_branch;
+        route.push(from);
+        to_branch.reverse();
+        route.extend(to_branch);
+
+        Ok(TreeRoute { route, pivot })
+    }
+
+    fn fetch_children(&self, parent: &Block::Hash) -> BlockchainResult<Vec<Block::Hash>> {
+        let data = self
+            .data
+            .db
+            .get(CHILDREN_COLUMN, parent.encode().as_slice())
+            .map_err(db_err)?;
+        if data.is_none() {
+            Ok(Vec::new())
+        } else {
+            Ok(
+                Vec::<Block::Hash>::decode(&mut data.unwrap().as_slice())
+                    .map_err(codec_error)?,
+            )
+        }
+    }
+
+    fn tx_store_child(
+        &self,
+        ctx: &mut UpdateContext<Block>,
+        parent: &Block::Hash,
+        child: Block::Hash,
+    ) -> BlockchainResult<()> {
+        let mut children = self.children_or_overlay(Some(&*ctx), parent)?;
+        if !children.contains(&child) {
+            children.push(child);
+        }
+        ctx.tx.put(
+            CHILDREN_COLUMN,
+            parent.encode().as_slice(),
+            children.encode().as_slice(),
+        );
+        ctx.children.insert(*parent, children);
+        Ok(())
+    }
+
+    fn fetch_leaves(&self) -> BlockchainResult<Vec<Block::Hash>> {
+        let data = self.data.db.get(META_COLUMN, LEAVES_KEY).map_err(db_err)?;
+        if data.is_none() {
+            Ok(Vec::new())
+        } else {
+            Ok(
+                Vec::<Block::Hash>::decode(&mut data.unwrap().as_slice())
+                    .map_err(codec_error)?,
+            )
+        }
+    }
+
+    fn tx_store_leaves(tx: &mut DBTransaction, leaves: &[Block::Hash]) {
+        tx.put(META_COLUMN, LEAVES_KEY, leaves.encode().as_slice());
+    }
+
+    fn tx_canonize(tx: &mut DBTransaction, number: NumberFor<Block>, hash: Block::Hash) {
+        tx.put(
+            LOOKUP_COLUMN,
+            number.encode().as_slice(),
+            hash.encode().as_slice(),
+        );
+    }
+
+    fn tx_decanonize(tx: &mut DBTransaction, number: NumberFor<Block>) {
+        tx.delete(LOOKUP_COLUMN, number.encode().as_slice());
+    }
+
+    fn new_update_context(&self) -> BlockchainResult<UpdateContext<Block>> {
+        let meta = self.fetch_meta()?.unwrap_or_else(|| StorageMeta {
+            best_hash: Default::default(),
+            best_number: Zero::zero(),
+            finalized_hash: Default::default(),
+            finalized_number: Zero::zero(),
+            genesis_hash: Default::default(),
+            non_finalized_blocks: 0,
+            oldest_retained_number: Zero::zero(),
+        });
+        let leaves = self.fetch_leaves()?;
+        Ok(UpdateContext {
+            tx: self.data.db.transaction(),
+            meta,
+            leaves,
+            headers: HashMap::new(),
+            children: HashMap::new(),
+        })
+    }
+
+    /// Import a single header against a pending batch, validating it the same
+    /// way `import_header` does, but reading parent/children/leaves state from
+    /// `ctx`'s overlay first so earlier headers in the same batch are visible
+    /// even though nothing has been committed to the database yet.
+    fn import_header_in_context(
+        &self,
+        ctx: &mut UpdateContext<Block>,
+        header: Block::Header,
+        state: NewBlockState,
+    ) -> BlockchainResult<()> {
+        if ctx.meta.non_finalized_blocks >= self.max_non_finalized_blocks_allowed {
+            return Err(BlockchainError::Backend(format!(
+                "Cannot import any more blocks, before finalizing previous blocks"
+            )));
+        }
+
+        if self.header_or_overlay(Some(&*ctx), header.hash())?.is_some() {
+            // We have already imported this block
+            return Ok(());
+        }
+
+        let first_imported_header = ctx.meta.best_hash == Default::default();
+        let header_hash = header.hash();
+
+        // Make the importing header visible through the overlay before resolving
+        // `becomes_best` below: `tree_route`'s `to` endpoint is this header, and it
+        // must already be resolvable via `header_or_overlay`, since neither the
+        // overlay nor the database has it yet at this point.
+        Self::tx_store_header(&mut ctx.tx, &header);
+        ctx.headers.insert(header_hash, header.clone());
+
+        if first_imported_header {
+            ctx.meta.genesis_hash = header_hash;
+            ctx.meta.best_hash = header_hash;
+            ctx.meta.best_number = *header.number();
+            Self::tx_canonize(&mut ctx.tx, *header.number(), header_hash);
+        } else {
+            let possible_parent_header = self.header_or_overlay(Some(&*ctx), *header.parent_hash())?;
+            if possible_parent_header.is_none() {
+                return Err(BlockchainError::UnknownBlock(format!(
+                    "Could not find parent of importing block"
+                )));
+            }
+            let parent_header = possible_parent_header.unwrap();
+            if *header.number() != *parent_header.number() + One::one() {
+                return Err(BlockchainError::NonSequentialFinalization(format!(
+                    "tried to import non sequential block. Expected block number: {}. Got: {}",
+                    *parent_header.number() + One::one(),
+                    *header.number()
+                )));
+            }
+
+            let parent_hash = *header.parent_hash();
+            self.tx_store_child(ctx, &parent_hash, header_hash)?;
+            ctx.leaves.retain(|leaf| *leaf != parent_hash);
+
+            let becomes_best = state.is_best() || *header.number() > ctx.meta.best_number;
+            if becomes_best {
+                let route = self.tree_route(Some(&*ctx), ctx.meta.best_hash, header_hash)?;
+                if route.common_block().number < ctx.meta.finalized_number {
+                    return Err(BlockchainError::Backend(format!(
+                        "Cannot reorganize past the finalized block"
+                    )));
+                }
+
+                for retracted in route.retracted() {
+                    Self::tx_decanonize(&mut ctx.tx, retracted.number);
+                }
+                for enacted in route.enacted() {
+                    Self::tx_canonize(&mut ctx.tx, enacted.number, enacted.hash);
+                }
+
+                ctx.meta.best_hash = header_hash;
+                ctx.meta.best_number = *header.number();
+            }
+        }
+
+        ctx.leaves.push(header_hash);
+        ctx.meta.non_finalized_blocks += 1;
+
+        Ok(())
+    }
+
+    /// Prune finalized headers older than the configured retention window,
+    /// advancing `meta.oldest_retained_number` as blocks are dropped. Stops
+    /// early at any header that is still a branch point (has more than one
+    /// recorded child), since removing it would lose the fork information a
+    /// still-tracked side branch needs. `children_overlay` must carry any
+    /// children-column rewrites staged by [`Self::prune_stale_forks`] that
+    /// have not been committed to the database yet, so the branch-point check
+    /// below sees them instead of stale, pre-prune child counts.
+    fn prune_finalized(
+        &self,
+        tx: &mut DBTransaction,
+        meta: &mut StorageMeta<NumberFor<Block>, Block::Hash>,
+        children_overlay: &HashMap<Block::Hash, Vec<Block::Hash>>,
+    ) -> BlockchainResult<()> {
+        let keep_finalized_blocks = match &self.pruning {
+            PruningMode::Archive => return Ok(()),
+            PruningMode::Keep {
+                keep_finalized_blocks,
+            } => *keep_finalized_blocks,
+        };
+
+        // Retains exactly `keep_finalized_blocks` finalized headers: the range
+        // `[target_oldest, finalized_number]`.
+        let target_oldest = if meta.finalized_number >= keep_finalized_blocks {
+            meta.finalized_number - keep_finalized_blocks + One::one()
+        } else {
+            Zero::zero()
+        };
+
+        let mut oldest = meta.oldest_retained_number;
+        while oldest < target_oldest {
+            let possible_hash = self.header_hash(oldest)?;
+            let hash = match possible_hash {
+                Some(hash) => hash,
+                None => break,
+            };
+
+            let children = match children_overlay.get(&hash) {
+                Some(children) => children.clone(),
+                None => self.fetch_children(&hash)?,
+            };
+            if children.len() > 1 {
+                // Still a branch point for a tracked side branch; stop pruning here.
+                break;
+            }
+
+            Self::tx_delete_header(tx, &hash);
+            Self::tx_decanonize(tx, oldest);
+            tx.delete(CHILDREN_COLUMN, hash.encode().as_slice());
+            self.evict_header_cache(&hash);
+
+            oldest = oldest + One::one();
+        }
+
+        meta.oldest_retained_number = oldest;
+        Ok(())
+    }
+
+    /// Drop any leaf whose branch diverged from the canonical chain at or below
+    /// `meta.finalized_number`. Once a block is finalized, a reorg crossing it is
+    /// rejected, so such a branch can never be finalized or become best again;
+    /// keeping it around would leak it forever in `non_finalized_blocks` and the
+    /// leaves set. Leaves that descend from the finalized block (i.e. forked
+    /// off above it) are left untouched, since they remain live candidates.
+    ///
+    /// Returns the children-column rewrites staged into `tx` for each affected
+    /// parent, keyed by parent hash, so callers that need an up-to-date child
+    /// count before those rewrites are committed (e.g. [`Self::prune_finalized`])
+    /// can consult it instead of reading the not-yet-updated database.
+    fn prune_stale_forks(
+        &self,
+        tx: &mut DBTransaction,
+        meta: &mut StorageMeta<NumberFor<Block>, Block::Hash>,
+    ) -> BlockchainResult<HashMap<Block::Hash, Vec<Block::Hash>>> {
+        let finalized_hash = meta.finalized_hash;
+        let leaves = self.fetch_leaves()?;
+        let mut remaining_leaves = Vec::with_capacity(leaves.len());
+        let mut removed = std::collections::HashSet::new();
+        let mut dead_children = Vec::new();
+
+        for leaf in leaves {
+            if leaf == finalized_hash {
+                remaining_leaves.push(leaf);
+                continue;
+            }
+
+            let route = self.tree_route(None, finalized_hash, leaf)?;
+            if route.common_block().hash == finalized_hash {
+                // Still descends from the finalized block; a live candidate.
+                remaining_leaves.push(leaf);
+                continue;
+            }
+
+            if let Some(topmost) = route.enacted().first() {
+                dead_children.push((route.common_block().hash, topmost.hash));
+            }
+
+            for stale in route.enacted() {
+                if removed.insert(stale.hash) {
+                    Self::tx_delete_header(tx, &stale.hash);
+                    tx.delete(CHILDREN_COLUMN, stale.hash.encode().as_slice());
+                    self.evict_header_cache(&stale.hash);
+                    if meta.non_finalized_blocks > 0 {
+                        meta.non_finalized_blocks -= 1;
+                    }
+                }
+            }
+        }
+
+        let mut children_overlay = HashMap::new();
+        for (parent, dead_child) in dead_children {
+            let mut children = match children_overlay.remove(&parent) {
+                Some(children) => children,
+                None => self.fetch_children(&parent)?,
+            };
+            children.retain(|child| *child != dead_child);
+            if children.is_empty() {
+                tx.delete(CHILDREN_COLUMN, parent.encode().as_slice());
+            } else {
+                tx.put(
+                    CHILDREN_COLUMN,
+                    parent.encode().as_slice(),
+                    children.encode().as_slice(),
+                );
+            }
+            children_overlay.insert(parent, children);
+        }
+
+        Self::tx_store_leaves(tx, &remaining_leaves);
+        Ok(children_overlay)
+    }
+
+    fn commit_update_context(&self, mut ctx: UpdateContext<Block>) -> BlockchainResult<()> {
+        Self::tx_store_leaves(&mut ctx.tx, &ctx.leaves);
+        Self::tx_store_meta(&mut ctx.tx, &ctx.meta);
+        self.data.db.write(ctx.tx).map_err(db_err)
+    }
+
+    /// Import a batch of headers atomically: every header is validated and
+    /// applied against one shared [`UpdateContext`], and the whole batch is
+    /// committed with a single `DBTransaction` write, either all landing or
+    /// none of it does.
+    pub fn import_headers(
+        &self,
+        headers: Vec<(Block::Header, NewBlockState)>,
+    ) -> BlockchainResult<()> {
+        let mut ctx = self.new_update_context()?;
+        for (header, state) in headers {
+            self.import_header_in_context(&mut ctx, header, state)?;
+        }
+        self.commit_update_context(ctx)
+    }
 }
 
-impl AuxStore for Storage {
+impl<Block> AuxStore for Storage<Block>
+where
+    Block: BlockT,
+{
     fn insert_aux<
         'a,
         'b: 'a,
@@ -191,11 +725,17 @@ impl AuxStore for Storage {
     }
 }
 
-impl<Block> HeaderBackend<Block> for Storage
+impl<Block> HeaderBackend<Block> for Storage<Block>
 where
     Block: BlockT,
 {
     fn header(&self, id: BlockId<Block>) -> BlockchainResult<Option<Block::Header>> {
+        if let BlockId::Hash(hash) = id {
+            if let Some(header) = self.header_cache.lock().get(&hash) {
+                return Ok(Some(header.clone()));
+            }
+        }
+
         let possible_header_key = self.id(id)?;
         if possible_header_key.is_none() {
             Ok(None)
@@ -212,6 +752,7 @@ where
                 let encoded_header = possible_encoded_header.unwrap();
                 let header =
                     Block::Header::decode(&mut encoded_header.as_slice()).map_err(codec_error)?;
+                self.header_cache.lock().put(header.hash(), header.clone());
                 Ok(Some(header))
             }
         }
@@ -219,6 +760,7 @@ where
 
     fn info(&self) -> BlockchainInfo<Block> {
         let meta = self.fetch_meta();
+        let number_leaves = self.fetch_leaves().map(|leaves| leaves.len()).unwrap_or(0);
         let default_info = BlockchainInfo {
             best_hash: Default::default(),
             best_number: Zero::zero(),
@@ -239,7 +781,7 @@ where
                     genesis_hash: meta.genesis_hash,
                     finalized_hash: meta.finalized_hash,
                     finalized_number: meta.finalized_number,
-                    number_leaves: 0,
+                    number_leaves,
                 }
             }
         } else {
@@ -270,11 +812,11 @@ where
     }
 
     fn hash(&self, number: NumberFor<Block>) -> BlockchainResult<Option<Block::Hash>> {
-        self.header_hash::<Block>(number)
+        self.header_hash(number)
     }
 }
 
-impl<Block> StorageT<Block> for Storage
+impl<Block> StorageT<Block> for Storage<Block>
 where
     Block: BlockT,
 {
@@ -282,86 +824,102 @@ where
     ///
     /// Takes new authorities, the leaf state of the new block, and
     /// any auxiliary storage updates to place in the same operation.
+    ///
+    /// Headers may land on side branches: they are always stored by hash and
+    /// tracked via the children/leaves index, but a branch only becomes
+    /// canonical (and gets its number→hash lookup entries written) once its
+    /// tip is higher than the current best block, or the header is imported
+    /// with `NewBlockState::Best`, at which point the route
+    /// between the old and new best is used to canonize/decanonize in place.
     fn import_header(
         &self,
         header: Block::Header,
         state: NewBlockState,
         aux_ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
     ) -> BlockchainResult<()> {
-        assert!(
-            state.is_best(),
-            "Since, we are only following one fork block state must need to be best"
-        );
+        let mut ctx = self.new_update_context()?;
+        self.import_header_in_context(&mut ctx, header, state)?;
+        for (key, value) in aux_ops {
+            match value {
+                Some(value) => ctx.tx.put(AUX_COLUMN, key.as_slice(), value.as_slice()),
+                None => ctx.tx.delete(AUX_COLUMN, key.as_slice()),
+            }
+        }
+        self.commit_update_context(ctx)
+    }
+
+    /// Set an existing block as new best block.
+    ///
+    /// Resolves `block` to an already-imported header, then uses the same
+    /// tree-route machinery as reorg-on-import to canonize/decanonize the
+    /// number→hash lookup entries between the current best and the target.
+    /// Rejects targets below the finalized chain, or routes whose common
+    /// ancestor falls below it, since that would require rewriting finalized
+    /// history.
+    fn set_head(&self, block: BlockId<Block>) -> BlockchainResult<()> {
+        let possible_target_header = self.header(block)?;
+        if possible_target_header.is_none() {
+            return Err(BlockchainError::UnknownBlock(format!(
+                "Could not find block to set as head"
+            )));
+        }
+        let target_header = possible_target_header.unwrap();
+        let target_hash = target_header.hash();
 
         let possible_meta = self.fetch_meta()?;
-        let mut meta: StorageMeta<NumberFor<Block>, Block::Hash> = if possible_meta.is_none() {
-            StorageMeta {
-                best_hash: Default::default(),
-                best_number: Zero::zero(),
-                finalized_hash: Default::default(),
-                finalized_number: Zero::zero(),
-                genesis_hash: Default::default(),
-                non_finalized_blocks: 0,
-            }
-        } else {
-            possible_meta.unwrap()
-        };
+        if possible_meta.is_none() {
+            return Err(BlockchainError::Backend(format!(
+                "Error: {}",
+                "Unable to get metadata about blockchain"
+            )));
+        }
+        let mut meta: StorageMeta<NumberFor<Block>, Block::Hash> = possible_meta.unwrap();
 
-        if meta.non_finalized_blocks >= self.max_non_finalized_blocks_allowed {
+        if *target_header.number() < meta.finalized_number {
             return Err(BlockchainError::Backend(format!(
-                "Cannot import any more blocks, before finalizing previous blocks"
+                "Cannot set head to a block below the finalized chain"
             )));
         }
 
-        let possible_header = self.header(BlockId::<Block>::Hash(header.hash()))?;
-        if possible_header.is_some() {
-            // We have already imported this block
+        if target_hash == meta.best_hash {
             return Ok(());
         }
 
-        let first_imported_header = meta.best_hash == Default::default();
-
-        // We need to check if this is child of last best header
-        if !first_imported_header {
-            let possible_parent_header = self.header(BlockId::<Block>::Hash(meta.best_hash))?;
-            if possible_parent_header.is_none() {
-                return Err(BlockchainError::UnknownBlock(format!(
-                    "Could not find parent of importing block"
-                )));
-            }
-            let parent_header = possible_parent_header.unwrap();
-            if *header.parent_hash() != parent_header.hash()
-                || header.number() <= parent_header.number()
-            {
-                return Err(BlockchainError::NotInFinalizedChain);
-            }
-            if *header.number() != meta.best_number + One::one() {
-                return Err(BlockchainError::NonSequentialFinalization(format!(
-                    "tried to import non sequential block. Expected block number: {}. Got: {}",
-                    meta.best_number + One::one(),
-                    *header.number()
-                )));
-            }
-        } else {
-            meta.genesis_hash = header.hash();
+        let route = self.tree_route(None, meta.best_hash, target_hash)?;
+        if route.common_block().number < meta.finalized_number {
+            return Err(BlockchainError::Backend(format!(
+                "Cannot set head across a reorg that crosses the finalized block"
+            )));
         }
 
-        meta.non_finalized_blocks += 1;
-        meta.best_hash = header.hash();
-        meta.best_number = *header.number();
-
         let mut tx = self.data.db.transaction();
+        for retracted in route.retracted() {
+            Self::tx_decanonize(&mut tx, retracted.number);
+        }
+        for enacted in route.enacted() {
+            Self::tx_canonize(&mut tx, enacted.number, enacted.hash);
+        }
+
+        meta.best_hash = target_hash;
+        meta.best_number = *target_header.number();
         Self::tx_store_meta(&mut tx, &meta);
-        Self::tx_store_header::<Block>(&mut tx, &header);
         self.data.db.write(tx).map_err(db_err)
     }
 
-    /// Set an existing block as new best block.
-    fn set_head(&self, block: BlockId<Block>) -> BlockchainResult<()> {
-        unimplemented!()
-    }
-
     /// Mark historic header as finalized.
+    ///
+    /// The block must be a direct child of the previously finalized block (or
+    /// the genesis block, the first time around), and it must be the block
+    /// that is currently canonical at that height (i.e. the one `hash()`
+    /// resolves to) — finalizing a sibling left behind by a fork is rejected,
+    /// since the caller almost certainly meant the branch that is actually
+    /// part of the best chain.
+    ///
+    /// Besides advancing `StorageMeta::oldest_retained_number` by dropping
+    /// headers older than the retention window (see [`PruningMode`]), this also
+    /// drops any sibling fork that diverged from the canonical chain at or
+    /// below the newly finalized block, since it can never be finalized or
+    /// become best again once a reorg past the finalized block is rejected.
     fn finalize_header(&self, block: BlockId<Block>) -> BlockchainResult<()> {
         let possible_to_be_finalized_header = self.header(block)?;
         if possible_to_be_finalized_header.is_none() {
@@ -388,15 +946,21 @@ where
             return Err(BlockchainError::NonSequentialFinalization(format!("Error: {}", "to be finalized block need to be child of last finalized block or first block itself")));
         }
 
+        if self.header_hash(*to_be_finalized_header.number())? != Some(to_be_finalized_header.hash()) {
+            return Err(BlockchainError::NonSequentialFinalization(format!(
+                "Error: {}",
+                "to be finalized block is not on the canonical chain"
+            )));
+        }
+
         meta.non_finalized_blocks -= 1;
         meta.finalized_hash = to_be_finalized_header.hash();
         meta.finalized_number = *to_be_finalized_header.number();
 
         let mut tx = self.data.db.transaction();
+        let children_overlay = self.prune_stale_forks(&mut tx, &mut meta)?;
+        self.prune_finalized(&mut tx, &mut meta, &children_overlay)?;
         Self::tx_store_meta(&mut tx, &meta);
-        if !first_block_to_be_finalized {
-            Self::tx_delete_header::<Block>(&mut tx, to_be_finalized_header.parent_hash());
-        }
         self.data.db.write(tx).map_err(db_err)
     }
 
@@ -414,7 +978,7 @@ where
     }
 }
 
-impl<Block> HeaderMetadata<Block> for Storage
+impl<Block> HeaderMetadata<Block> for Storage<Block>
 where
     Block: BlockT,
 {
@@ -424,6 +988,10 @@ where
         &self,
         hash: Block::Hash,
     ) -> Result<CachedHeaderMetadata<Block>, Self::Error> {
+        if let Some(cached) = self.header_metadata_cache.lock().get(&hash) {
+            return Ok(cached.clone());
+        }
+
         let possible_header = self.header(BlockId::<Block>::Hash(hash))?;
         if possible_header.is_none() {
             Err(BlockchainError::UnknownBlock(format!(
@@ -432,19 +1000,368 @@ where
             )))
         } else {
             let header = possible_header.unwrap();
-            Ok(CachedHeaderMetadata::from(&header))
+            let metadata = CachedHeaderMetadata::from(&header);
+            self.header_metadata_cache.lock().put(hash, metadata.clone());
+            Ok(metadata)
         }
     }
 
-    fn insert_header_metadata(
-        &self,
-        hash: Block::Hash,
-        header_metadata: CachedHeaderMetadata<Block>,
-    ) {
-        unimplemented!()
+    fn insert_header_metadata(&self, hash: Block::Hash, header_metadata: CachedHeaderMetadata<Block>) {
+        self.header_metadata_cache.lock().put(hash, header_metadata);
     }
 
     fn remove_header_metadata(&self, hash: Block::Hash) {
-        unimplemented!()
+        self.header_metadata_cache.lock().pop(&hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sp_runtime::testing::{Block as TestRawBlock, ExtrinsicWrapper, Header as TestHeader};
+    use std::sync::Arc;
+
+    type TestBlock = TestRawBlock<ExtrinsicWrapper<u64>>;
+
+    fn test_storage(max_non_finalized_blocks_allowed: u64, pruning: PruningMode<u64>) -> Storage<TestBlock> {
+        let db = Arc::new(kvdb_memorydb::create(5));
+        Storage::new(Data { db }, max_non_finalized_blocks_allowed, 16, pruning)
+    }
+
+    fn header(number: u64, parent_hash: <TestBlock as BlockT>::Hash) -> TestHeader {
+        TestHeader {
+            parent_hash,
+            number,
+            state_root: Default::default(),
+            extrinsics_root: Default::default(),
+            digest: Default::default(),
+        }
+    }
+
+    fn genesis() -> TestHeader {
+        header(0, Default::default())
+    }
+
+    #[test]
+    fn imports_two_blocks_in_a_line() {
+        let storage = test_storage(1024, PruningMode::Archive);
+        let genesis = genesis();
+        let genesis_hash = genesis.hash();
+        storage
+            .import_header(genesis, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let child = header(1, genesis_hash);
+        let child_hash = child.hash();
+        storage
+            .import_header(child, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let info = storage.info();
+        assert_eq!(info.best_hash, child_hash);
+        assert_eq!(info.best_number, 1);
+        assert_eq!(storage.hash(1).unwrap(), Some(child_hash));
+    }
+
+    #[test]
+    fn reorgs_to_a_fork_once_it_overtakes_best() {
+        let storage = test_storage(1024, PruningMode::Archive);
+        let genesis = genesis();
+        let genesis_hash = genesis.hash();
+        storage
+            .import_header(genesis, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let a1 = header(1, genesis_hash);
+        let a1_hash = a1.hash();
+        storage
+            .import_header(a1, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let mut b1 = header(1, genesis_hash);
+        b1.state_root = [1u8; 32].into();
+        let b1_hash = b1.hash();
+        storage
+            .import_header(b1, NewBlockState::Normal, Vec::new())
+            .unwrap();
+
+        // Side branch doesn't overtake best yet.
+        assert_eq!(storage.info().best_hash, a1_hash);
+
+        let b2 = header(2, b1_hash);
+        let b2_hash = b2.hash();
+        storage
+            .import_header(b2, NewBlockState::Normal, Vec::new())
+            .unwrap();
+
+        // b2 extends the fork past a1's height, so it becomes best.
+        let info = storage.info();
+        assert_eq!(info.best_hash, b2_hash);
+        assert_eq!(storage.hash(1).unwrap(), Some(b1_hash));
+        assert_eq!(storage.hash(2).unwrap(), Some(b2_hash));
+    }
+
+    #[test]
+    fn rejects_reorg_crossing_the_finalized_block() {
+        let storage = test_storage(1024, PruningMode::Archive);
+        let genesis = genesis();
+        let genesis_hash = genesis.hash();
+        storage
+            .import_header(genesis, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let a1 = header(1, genesis_hash);
+        let a1_hash = a1.hash();
+        storage
+            .import_header(a1, NewBlockState::Best, Vec::new())
+            .unwrap();
+        storage.finalize_header(BlockId::Hash(genesis_hash)).unwrap();
+        storage.finalize_header(BlockId::Hash(a1_hash)).unwrap();
+
+        let mut b1 = header(1, genesis_hash);
+        b1.state_root = [1u8; 32].into();
+        let result = storage.import_header(b1, NewBlockState::Best, Vec::new());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn windowed_prune_stops_at_a_branch_point() {
+        let storage = test_storage(
+            1024,
+            PruningMode::Keep {
+                keep_finalized_blocks: 0,
+            },
+        );
+        let genesis = genesis();
+        let genesis_hash = genesis.hash();
+        storage
+            .import_header(genesis, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let a1 = header(1, genesis_hash);
+        storage
+            .import_header(a1, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let mut b1 = header(1, genesis_hash);
+        b1.state_root = [1u8; 32].into();
+        storage
+            .import_header(b1, NewBlockState::Normal, Vec::new())
+            .unwrap();
+
+        // Exercise `prune_finalized` directly, ahead of `prune_stale_forks`, so
+        // genesis still has two recorded children (a1 and the b1 fork) when the
+        // window walks over it. Pruning it away would lose that fork
+        // information, so the walk must stop before it.
+        let mut meta = storage
+            .fetch_meta::<u64, <TestBlock as BlockT>::Hash>()
+            .unwrap()
+            .unwrap();
+        meta.finalized_number = 1;
+        let mut tx = storage.data.db.transaction();
+        storage
+            .prune_finalized(&mut tx, &mut meta, &HashMap::new())
+            .unwrap();
+
+        assert_eq!(meta.oldest_retained_number, 0);
+        assert!(storage.header(BlockId::Hash(genesis_hash)).unwrap().is_some());
+    }
+
+    #[test]
+    fn set_head_reorgs_to_a_sibling_fork() {
+        let storage = test_storage(1024, PruningMode::Archive);
+        let genesis = genesis();
+        let genesis_hash = genesis.hash();
+        storage
+            .import_header(genesis, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let a1 = header(1, genesis_hash);
+        let a1_hash = a1.hash();
+        storage
+            .import_header(a1, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let mut b1 = header(1, genesis_hash);
+        b1.state_root = [1u8; 32].into();
+        let b1_hash = b1.hash();
+        storage
+            .import_header(b1, NewBlockState::Normal, Vec::new())
+            .unwrap();
+
+        assert_eq!(storage.info().best_hash, a1_hash);
+
+        storage.set_head(BlockId::Hash(b1_hash)).unwrap();
+
+        let info = storage.info();
+        assert_eq!(info.best_hash, b1_hash);
+        assert_eq!(storage.hash(1).unwrap(), Some(b1_hash));
+    }
+
+    #[test]
+    fn set_head_rolls_back_to_an_ancestor() {
+        let storage = test_storage(1024, PruningMode::Archive);
+        let genesis = genesis();
+        let genesis_hash = genesis.hash();
+        storage
+            .import_header(genesis, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let a1 = header(1, genesis_hash);
+        let a1_hash = a1.hash();
+        storage
+            .import_header(a1, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let a2 = header(2, a1_hash);
+        let a2_hash = a2.hash();
+        storage
+            .import_header(a2, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        assert_eq!(storage.info().best_hash, a2_hash);
+
+        // Rolling back to a plain ancestor: the route's `enacted` side is empty.
+        storage.set_head(BlockId::Hash(a1_hash)).unwrap();
+
+        let info = storage.info();
+        assert_eq!(info.best_hash, a1_hash);
+        assert_eq!(info.best_number, 1);
+        assert_eq!(storage.hash(2).unwrap(), None);
+    }
+
+    #[test]
+    fn set_head_rejects_target_below_finalized() {
+        let storage = test_storage(1024, PruningMode::Archive);
+        let genesis = genesis();
+        let genesis_hash = genesis.hash();
+        storage
+            .import_header(genesis, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let a1 = header(1, genesis_hash);
+        let a1_hash = a1.hash();
+        storage
+            .import_header(a1, NewBlockState::Best, Vec::new())
+            .unwrap();
+        storage.finalize_header(BlockId::Hash(genesis_hash)).unwrap();
+        storage.finalize_header(BlockId::Hash(a1_hash)).unwrap();
+
+        let result = storage.set_head(BlockId::Hash(genesis_hash));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn set_head_rejects_reorg_crossing_finalized() {
+        let storage = test_storage(1024, PruningMode::Archive);
+        let genesis = genesis();
+        let genesis_hash = genesis.hash();
+        storage
+            .import_header(genesis, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let a1 = header(1, genesis_hash);
+        let a1_hash = a1.hash();
+        storage
+            .import_header(a1, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let a2 = header(2, a1_hash);
+        storage
+            .import_header(a2, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        storage.finalize_header(BlockId::Hash(genesis_hash)).unwrap();
+        storage.finalize_header(BlockId::Hash(a1_hash)).unwrap();
+
+        // A side leaf forking off below the finalized block, imported after
+        // finalization; still importable since it never becomes best, but no
+        // longer reachable from the best chain
+        // without crossing the finalized block.
+        let mut b1 = header(1, genesis_hash);
+        b1.state_root = [1u8; 32].into();
+        let b1_hash = b1.hash();
+        storage
+            .import_header(b1, NewBlockState::Normal, Vec::new())
+            .unwrap();
+
+        let result = storage.set_head(BlockId::Hash(b1_hash));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn import_headers_batch_is_all_or_nothing() {
+        let storage = test_storage(1024, PruningMode::Archive);
+        let genesis = genesis();
+        let genesis_hash = genesis.hash();
+
+        // The second header skips a number, which import_header_in_context
+        // rejects; the whole batch, including the otherwise-valid genesis,
+        // must not land.
+        let bad = header(5, genesis_hash);
+        let result = storage.import_headers(vec![
+            (genesis, NewBlockState::Best),
+            (bad, NewBlockState::Best),
+        ]);
+        assert!(result.is_err());
+
+        assert!(storage.header(BlockId::Hash(genesis_hash)).unwrap().is_none());
+        assert_eq!(storage.info().best_number, 0);
+    }
+
+    #[test]
+    fn lru_cache_is_evicted_when_a_header_is_pruned() {
+        let storage = test_storage(
+            1024,
+            PruningMode::Keep {
+                keep_finalized_blocks: 1,
+            },
+        );
+        let genesis = genesis();
+        let genesis_hash = genesis.hash();
+        storage
+            .import_header(genesis, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let a1 = header(1, genesis_hash);
+        let a1_hash = a1.hash();
+        storage
+            .import_header(a1, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        // Populate the header cache for genesis.
+        assert!(storage.header(BlockId::Hash(genesis_hash)).unwrap().is_some());
+        assert!(storage.header_cache.lock().contains(&genesis_hash));
+
+        storage.finalize_header(BlockId::Hash(genesis_hash)).unwrap();
+        storage.finalize_header(BlockId::Hash(a1_hash)).unwrap();
+
+        // keep_finalized_blocks=1 retains only a1; genesis is pruned and must be
+        // evicted from the cache along with the backing store.
+        assert!(!storage.header_cache.lock().contains(&genesis_hash));
+        assert!(storage.header(BlockId::Hash(genesis_hash)).unwrap().is_none());
+        assert!(storage.header(BlockId::Hash(a1_hash)).unwrap().is_some());
+    }
+
+    #[test]
+    fn archive_pruning_never_deletes_headers() {
+        let storage = test_storage(1024, PruningMode::Archive);
+        let genesis = genesis();
+        let genesis_hash = genesis.hash();
+        storage
+            .import_header(genesis, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        let a1 = header(1, genesis_hash);
+        let a1_hash = a1.hash();
+        storage
+            .import_header(a1, NewBlockState::Best, Vec::new())
+            .unwrap();
+
+        storage.finalize_header(BlockId::Hash(genesis_hash)).unwrap();
+        storage.finalize_header(BlockId::Hash(a1_hash)).unwrap();
+
+        assert_eq!(storage.oldest_retained_number().unwrap(), 0);
+        assert!(storage.header(BlockId::Hash(genesis_hash)).unwrap().is_some());
     }
 }